@@ -17,15 +17,31 @@ pub struct Keystroke {
     pub interpreted: String,
 }
 
+impl Keystroke {
+    /// A stable string identifier for this keystroke, used as a map key by
+    /// formats (JSON, plist) whose map keys must be strings. Round-trips
+    /// through [`Keystroke::from_id`].
+    pub fn to_id(&self) -> String {
+        serde_json::to_string(self).expect("Keystroke serialization is infallible")
+    }
+
+    /// Parse a string produced by [`Keystroke::to_id`] back into a
+    /// `Keystroke`.
+    pub fn from_id(id: &str) -> Result<Keystroke, serde_json::Error> {
+        serde_json::from_str(id)
+    }
+}
+
 /// A corpus is a list of keystrokes
 pub type Corpus = Vec<Keystroke>;
 
 /// A keystroke heatmap is a map of keystrokes to the number of times they have been pressed.
 pub type KeystrokeHeatmap = HashMap<Keystroke, KeystrokeCount>;
-/// A bigram heatmap is a map of bigrams to the number of times they have been pressed.
-pub type BigramHeatmap = HashMap<(Keystroke, Keystroke), KeystrokeCount>;
-/// A trigram heatmap is a map of trigrams to the number of times they have been pressed.
-pub type TrigramHeatmap = HashMap<(Keystroke, Keystroke, Keystroke), KeystrokeCount>;
+/// An n-gram heatmap maps a run of keystrokes, oldest to newest, to the
+/// number of times that exact run was typed in succession. A run's length
+/// (2 for a bigram, 3 for a trigram, and so on) can be anywhere up to the
+/// store's configured order.
+pub type NgramHeatmap = HashMap<Vec<Keystroke>, KeystrokeCount>;
 
 /// A generator is a pseudo random Keystroke generator based on typing heatmaps.
 #[derive(Clone, Debug)]
@@ -34,23 +50,23 @@ pub struct Generator {
     keystrokes: Vec<Keystroke>,
     /// The weights of each keystroke
     weights: Vec<u32>,
-    /// Lookup table for bigrams to a vector of (index, weight) possible next keystroke
-    bigram_lookup: HashMap<usize, Vec<(usize, KeystrokeCount)>>,
-    /// Lookup table for trigrams to a vector of (index, weight) possible next keystroke
-    trigram_lookup: HashMap<(usize, usize), Vec<(usize, KeystrokeCount)>>,
-    /// The last two keystrokes index
-    preceeding: [Option<usize>; 2],
+    /// Lookup table from a preceding context (oldest to newest keystroke
+    /// indices) to the (index, weight) of possible next keystrokes.
+    ngram_lookup: HashMap<Vec<usize>, Vec<(usize, KeystrokeCount)>>,
+    /// Highest n-gram order to consider, i.e. the longest context length is
+    /// `order - 1`.
+    order: usize,
+    /// The preceding keystroke indices, most recent first, capped at
+    /// `order - 1` entries.
+    preceeding: Vec<usize>,
     /// Random number generator
     rng: ThreadRng,
 }
 
 impl Generator {
-    /// Create a new generator from heatmaps.
-    pub fn new(
-        keystrokes: &KeystrokeHeatmap,
-        bigrams: &BigramHeatmap,
-        trigrams: &TrigramHeatmap,
-    ) -> Generator {
+    /// Create a new generator from heatmaps, considering n-grams up to
+    /// `order` (a trigram-only generator uses `order = 3`).
+    pub fn new(keystrokes: &KeystrokeHeatmap, ngrams: &NgramHeatmap, order: usize) -> Generator {
         // Unzip the keystrokes and weights
         let (keys, weights): (Vec<_>, Vec<_>) = keystrokes.clone().into_iter().unzip();
         let keylookup: HashMap<Keystroke, usize> = keys
@@ -60,26 +76,25 @@ impl Generator {
             .map(|(i, v)| (v, i))
             .collect();
 
-        let mut bigram_lookup = HashMap::new();
-        bigrams.iter().for_each(|(k, v)| {
-            let index = keylookup[&k.0];
-            let bigram = bigram_lookup.entry(index).or_insert(Vec::new());
-            bigram.push((keylookup[&k.1], *v));
-        });
-
-        let mut trigram_lookup = HashMap::new();
-        trigrams.iter().for_each(|(k, v)| {
-            let index = (keylookup[&k.0], keylookup[&k.1]);
-            let trigram = trigram_lookup.entry(index).or_insert(Vec::new());
-            trigram.push((keylookup[&k.2], *v));
+        let mut ngram_lookup: HashMap<Vec<usize>, Vec<(usize, KeystrokeCount)>> = HashMap::new();
+        ngrams.iter().for_each(|(gram, count)| {
+            if gram.len() < 2 {
+                return;
+            }
+            let context: Vec<usize> = gram[..gram.len() - 1]
+                .iter()
+                .map(|ks| keylookup[ks])
+                .collect();
+            let next = keylookup[&gram[gram.len() - 1]];
+            ngram_lookup.entry(context).or_default().push((next, *count));
         });
 
         Generator {
             keystrokes: keys,
             weights,
-            bigram_lookup,
-            trigram_lookup,
-            preceeding: [None, None],
+            ngram_lookup,
+            order,
+            preceeding: Vec::new(),
             rng: rand::thread_rng(),
         }
     }
@@ -88,29 +103,26 @@ impl Generator {
     pub fn generate_random_keystroke(&mut self) -> Keystroke {
         let mut weights = self.weights.clone();
 
-        // Update weights with bigram if we have a preceeding keystroke
-        if let Some(index) = self.preceeding[0] {
-            self.bigram_lookup
-                .get(&index)
-                .unwrap_or(&Vec::new())
-                .iter()
-                .for_each(|(i, w)| weights[*i] += w);
-        }
-        // Update weights with trigram if we have two preceeding keystrokes
-        if let Some(index) = self.preceeding[1] {
-            self.trigram_lookup
-                .get(&(index, self.preceeding[0].unwrap()))
-                .unwrap_or(&Vec::new())
-                .iter()
-                .for_each(|(i, w)| weights[*i] += w);
+        // Back off from the longest available context down to the unigram
+        // weights: the first (longest) context with recorded continuations
+        // wins and its weights are added on top of the unigram prior.
+        let max_context = self.order.saturating_sub(1).min(self.preceeding.len());
+        for len in (1..=max_context).rev() {
+            let mut context = self.preceeding[..len].to_vec();
+            context.reverse();
+            if let Some(next) = self.ngram_lookup.get(&context) {
+                next.iter().for_each(|(i, w)| weights[*i] += w);
+                break;
+            }
         }
+
         // generate weighted index
         let weighted_index = WeightedIndex::new(&weights).expect("weights index should be valid");
 
         // generate the next index
         let index = weighted_index.sample(&mut self.rng);
-        self.preceeding[1] = self.preceeding[0];
-        self.preceeding[0] = Some(index);
+        self.preceeding.insert(0, index);
+        self.preceeding.truncate(self.order.saturating_sub(1));
         // return the keystroke
         self.keystrokes[index].clone()
     }
@@ -124,3 +136,45 @@ impl Iterator for Generator {
         Some(keystroke)
     }
 }
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_prefers_longest_context() {
+        let a = Keystroke {
+            key: Key::KeyA,
+            interpreted: "a".to_string(),
+        };
+        let b = Keystroke {
+            key: Key::KeyB,
+            interpreted: "b".to_string(),
+        };
+        let c = Keystroke {
+            key: Key::KeyC,
+            interpreted: "c".to_string(),
+        };
+
+        let mut ngram_lookup: HashMap<Vec<usize>, Vec<(usize, KeystrokeCount)>> = HashMap::new();
+        // 1-gram context "A" -> C. Would win if the backoff skipped straight
+        // to the shortest context instead of trying the longest one first.
+        ngram_lookup.insert(vec![0], vec![(2, 1_000_000)]);
+        // 2-gram context "A, A" -> B. The longest context available given
+        // `preceeding` below, so it should be the one that wins.
+        ngram_lookup.insert(vec![0, 0], vec![(1, 1_000_000)]);
+
+        let mut generator = Generator {
+            keystrokes: vec![a, b.clone(), c],
+            weights: vec![0, 0, 0],
+            ngram_lookup,
+            order: 3,
+            preceeding: vec![0, 0], // most recent first: A, A
+            rng: rand::thread_rng(),
+        };
+
+        assert_eq!(generator.generate_random_keystroke(), b);
+    }
+}
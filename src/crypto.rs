@@ -0,0 +1,273 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+
+use argon2::Argon2;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32, Nonce, StreamBE32};
+use chacha20poly1305::{ChaCha20Poly1305, Key};
+use rand::RngCore;
+
+/// Magic bytes identifying an encrypted store file. Anything else is read as
+/// a plaintext store, in whichever [`crate::format::Format`] it was saved.
+pub const MAGIC: &[u8; 4] = b"KSE1";
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 7;
+const BUF_LEN: usize = 4096;
+
+/// Environment variable consulted for the store passphrase before falling
+/// back to an interactive prompt.
+pub const PASSPHRASE_ENV: &str = "KONSONE_PASSPHRASE";
+
+#[derive(Debug)]
+pub enum CryptoError {
+    Io(std::io::Error),
+    Aead(String),
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::Io(e) => write!(f, "io error: {}", e),
+            CryptoError::Aead(msg) => write!(f, "authentication failed: {}", msg),
+        }
+    }
+}
+
+impl Error for CryptoError {}
+
+impl From<std::io::Error> for CryptoError {
+    fn from(e: std::io::Error) -> Self {
+        CryptoError::Io(e)
+    }
+}
+
+/// Derive a 32-byte key from a passphrase and salt using Argon2id.
+fn derive_key(passphrase: &str, salt: &[u8; SALT_LEN]) -> Result<[u8; 32], CryptoError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| CryptoError::Aead(e.to_string()))?;
+    Ok(key)
+}
+
+/// Read the passphrase from `KONSONE_PASSPHRASE`, or prompt on stdin if unset.
+pub fn passphrase() -> Result<String, Box<dyn Error>> {
+    if let Ok(pass) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(pass);
+    }
+    Ok(rpassword::prompt_password("Store passphrase: ")?)
+}
+
+/// Sniff the first bytes of `rdr` to tell whether they are an encrypted
+/// store. Consumes up to four bytes from `rdr` and returns whether they
+/// matched [`MAGIC`]; on a match the reader is left just past the magic,
+/// ready for [`DecryptingReader::new`].
+pub fn has_magic<R: Read>(rdr: &mut R) -> Result<bool, std::io::Error> {
+    let mut buf = [0u8; 4];
+    match rdr.read_exact(&mut buf) {
+        Ok(()) => Ok(&buf == MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Wrap `writer` so that everything written to it is encrypted with
+/// ChaCha20-Poly1305 in STREAM mode and framed behind a `[magic][salt][nonce]`
+/// header. Chunks are encrypted and flushed as they arrive so the whole
+/// serialized store is never buffered in memory at once.
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    encryptor: Option<EncryptorBE32<ChaCha20Poly1305>>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub fn new(mut inner: W, passphrase: &str) -> Result<Self, CryptoError> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce_bytes);
+
+        let key = derive_key(passphrase, &salt)?;
+        let nonce = Nonce::<ChaCha20Poly1305, StreamBE32<ChaCha20Poly1305>>::from_slice(&nonce_bytes);
+        let encryptor = EncryptorBE32::new(Key::from_slice(&key), nonce);
+
+        inner.write_all(MAGIC)?;
+        inner.write_all(&salt)?;
+        inner.write_all(&nonce_bytes)?;
+
+        Ok(Self {
+            inner,
+            encryptor: Some(encryptor),
+            buf: Vec::with_capacity(BUF_LEN),
+        })
+    }
+
+    /// Flush the current chunk as a non-final STREAM message.
+    fn flush_chunk(&mut self) -> Result<(), CryptoError> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let encryptor = self.encryptor.as_mut().expect("writer already finished");
+        let ciphertext = encryptor
+            .encrypt_next(self.buf.as_slice())
+            .map_err(|e| CryptoError::Aead(e.to_string()))?;
+        self.buf.clear();
+        self.inner
+            .write_all(&(ciphertext.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&ciphertext)?;
+        Ok(())
+    }
+
+    /// Encrypt and emit the final STREAM message, consuming the writer.
+    pub fn finish(mut self) -> Result<W, CryptoError> {
+        let encryptor = self.encryptor.take().expect("writer already finished");
+        let ciphertext = encryptor
+            .encrypt_last(self.buf.as_slice())
+            .map_err(|e| CryptoError::Aead(e.to_string()))?;
+        self.inner
+            .write_all(&(ciphertext.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&ciphertext)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        while self.buf.len() >= BUF_LEN {
+            let rest = self.buf.split_off(BUF_LEN);
+            self.flush_chunk().map_err(std::io::Error::other)?;
+            self.buf = rest;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader side of [`EncryptingWriter`]: expects the magic bytes to already
+/// have been consumed by the caller (after checking [`has_magic`]), reads the
+/// `[salt][nonce]` header itself, then decrypts the STREAM-framed chunks as
+/// they are read.
+pub struct DecryptingReader<R: Read> {
+    inner: BufReader<R>,
+    decryptor: Option<DecryptorBE32<ChaCha20Poly1305>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    pub fn new(inner: R, passphrase: &str) -> Result<Self, CryptoError> {
+        let mut inner = BufReader::new(inner);
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        inner.read_exact(&mut salt)?;
+        inner.read_exact(&mut nonce_bytes)?;
+
+        let key = derive_key(passphrase, &salt)?;
+        let nonce = Nonce::<ChaCha20Poly1305, StreamBE32<ChaCha20Poly1305>>::from_slice(&nonce_bytes);
+        let decryptor = DecryptorBE32::new(Key::from_slice(&key), nonce);
+
+        Ok(Self {
+            inner,
+            decryptor: Some(decryptor),
+            buf: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    fn read_chunk(&mut self) -> std::io::Result<bool> {
+        let mut len_bytes = [0u8; 4];
+        match self.inner.read_exact(&mut len_bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        let len = u32::from_be_bytes(len_bytes) as usize;
+        let mut ciphertext = vec![0u8; len];
+        self.inner.read_exact(&mut ciphertext)?;
+
+        // We can't know ahead of time whether this is the last chunk, so peek
+        // for more data without consuming it (`fill_buf` doesn't advance the
+        // stream) and fall back to `decrypt_last` once it's exhausted.
+        let at_eof = self.inner.fill_buf()?.is_empty();
+        let decryptor = self.decryptor.as_mut().expect("reader already finished");
+        self.buf = if at_eof {
+            self.decryptor
+                .take()
+                .unwrap()
+                .decrypt_last(ciphertext.as_slice())
+                .map_err(|e| std::io::Error::other(CryptoError::Aead(e.to_string())))?
+        } else {
+            decryptor
+                .decrypt_next(ciphertext.as_slice())
+                .map_err(|e| std::io::Error::other(CryptoError::Aead(e.to_string())))?
+        };
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.buf.len() && (self.decryptor.is_none() || !self.read_chunk()?) {
+            return Ok(0);
+        }
+        let n = std::cmp::min(out.len(), self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(plaintext: &[u8]) -> Vec<u8> {
+        let mut ciphertext = Vec::new();
+        let mut writer = EncryptingWriter::new(&mut ciphertext, "hunter2").unwrap();
+        writer.write_all(plaintext).unwrap();
+        writer.finish().unwrap();
+
+        let mut cursor = std::io::Cursor::new(ciphertext);
+        assert!(has_magic(&mut cursor).unwrap());
+        let mut reader = DecryptingReader::new(cursor, "hunter2").unwrap();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_roundtrip_small() {
+        let data = b"the quick brown fox";
+        assert_eq!(roundtrip(data), data);
+    }
+
+    #[test]
+    fn test_roundtrip_spans_multiple_chunks() {
+        // BUF_LEN is 4096; make sure data that crosses several chunk
+        // boundaries survives without losing the overflow past the first one.
+        let data: Vec<u8> = (0..BUF_LEN * 3 + 17).map(|i| (i % 251) as u8).collect();
+        assert_eq!(roundtrip(&data), data);
+    }
+
+    #[test]
+    fn test_wrong_passphrase_fails() {
+        let mut ciphertext = Vec::new();
+        let writer = EncryptingWriter::new(&mut ciphertext, "hunter2").unwrap();
+        writer.finish().unwrap();
+
+        let mut cursor = std::io::Cursor::new(ciphertext);
+        assert!(has_magic(&mut cursor).unwrap());
+        let mut reader = DecryptingReader::new(cursor, "wrong").unwrap();
+        let mut out = Vec::new();
+        assert!(reader.read_to_end(&mut out).is_err());
+    }
+}
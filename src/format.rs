@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::corpus::{Keystroke, KeystrokeHeatmap, NgramHeatmap};
+use crate::store::Store;
+
+/// Storage format for a [`Store`]. `Bare` is the compact, opaque default;
+/// `Json` and `Plist` trade size for interoperability with external
+/// keyboard-analysis tooling and, for `Plist`, macOS property-list viewers.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Bare,
+    Json,
+    Plist,
+}
+
+impl Format {
+    /// Guess the format of a store from its file extension, falling back to
+    /// `Bare` for unknown or missing extensions.
+    pub fn from_path(path: &str) -> Format {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Format::Json,
+            Some("plist") => Format::Plist,
+            _ => Format::Bare,
+        }
+    }
+
+    /// Guess the format from the leading bytes of a store, for files opened
+    /// without a recognizable extension. Returns the format alongside a
+    /// reader that still yields the sniffed bytes, so nothing is lost.
+    pub fn sniff<R: Read>(mut rdr: R) -> Result<(Format, impl Read), std::io::Error> {
+        let mut buf = [0u8; 1];
+        let read = rdr.read(&mut buf)?;
+        let format = match buf.first() {
+            Some(b'{') if read > 0 => Format::Json,
+            Some(b'<') if read > 0 => Format::Plist,
+            _ => Format::Bare,
+        };
+        Ok((format, std::io::Cursor::new(buf[..read].to_vec()).chain(rdr)))
+    }
+
+    /// Serialize `store` to `writer` in this format.
+    pub fn save(&self, store: &Store, writer: impl Write) -> Result<(), Box<dyn Error>> {
+        match self {
+            Format::Bare => Ok(serde_bare::to_writer(writer, store)?),
+            Format::Json => Ok(serde_json::to_writer_pretty(writer, &Portable::from(store))?),
+            Format::Plist => Ok(plist::to_writer_xml(writer, &Portable::from(store))?),
+        }
+    }
+
+    /// Deserialize a store named `filename` from `reader` in this format.
+    pub fn load(&self, reader: impl Read, filename: String) -> Result<Store, Box<dyn Error>> {
+        match self {
+            Format::Bare => Ok(serde_bare::from_reader(reader)?),
+            Format::Json => {
+                let portable: Portable = serde_json::from_reader(reader)?;
+                portable.into_store(filename)
+            }
+            Format::Plist => {
+                let portable: Portable = plist::from_reader_xml(reader)?;
+                portable.into_store(filename)
+            }
+        }
+    }
+}
+
+/// A string-keyed mirror of [`Store`]'s heatmaps, for formats (JSON, plist)
+/// whose map keys must be strings. `Keystroke` is flattened into a stable
+/// id via [`Keystroke::to_id`] and reassembled on load.
+///
+/// `ngrams` is an array of entries rather than a map flattened under a
+/// joined key: an n-gram's component ids would need concatenating with some
+/// separator, and any separator character risks landing inside a plist's
+/// XML body where it can't be represented (raw control bytes aren't valid
+/// XML, escaped or not).
+#[derive(Serialize, Deserialize)]
+struct Portable {
+    order: usize,
+    heatmap: HashMap<String, u32>,
+    ngrams: Vec<NgramEntry>,
+}
+
+/// One n-gram's component keystroke ids, oldest to newest, and the number
+/// of times that run was typed.
+#[derive(Serialize, Deserialize)]
+struct NgramEntry {
+    keys: Vec<String>,
+    count: u32,
+}
+
+impl From<&Store> for Portable {
+    fn from(store: &Store) -> Self {
+        Portable {
+            order: store.order(),
+            heatmap: store.heatmap.iter().map(|(ks, count)| (ks.to_id(), *count)).collect(),
+            ngrams: store
+                .ngrams
+                .iter()
+                .map(|(ks, count)| NgramEntry {
+                    keys: ks.iter().map(Keystroke::to_id).collect(),
+                    count: *count,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Portable {
+    fn into_store(self, filename: String) -> Result<Store, Box<dyn Error>> {
+        let heatmap: KeystrokeHeatmap = self
+            .heatmap
+            .into_iter()
+            .map(|(id, count)| Ok((Keystroke::from_id(&id)?, count)))
+            .collect::<Result<_, serde_json::Error>>()?;
+        let ngrams: NgramHeatmap = self
+            .ngrams
+            .into_iter()
+            .map(|entry| {
+                let keystrokes = entry
+                    .keys
+                    .iter()
+                    .map(|id| Keystroke::from_id(id))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok((keystrokes, entry.count))
+            })
+            .collect::<Result<_, serde_json::Error>>()?;
+        Ok(Store::from_heatmaps(filename, heatmap, ngrams, self.order))
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::Keystroke;
+    use rdev::Key;
+
+    fn sample_store() -> Store {
+        let mut store = Store::new("keymap".to_string());
+        store.set_order(5);
+        let ka = Keystroke {
+            key: Key::KeyA,
+            interpreted: "a".to_string(),
+        };
+        let kb = Keystroke {
+            key: Key::KeyB,
+            interpreted: "b".to_string(),
+        };
+        store.heatmap.insert(ka.clone(), 3);
+        store.ngrams.insert(vec![ka.clone(), kb.clone()], 2);
+        store.ngrams.insert(vec![ka.clone(), kb.clone(), ka.clone()], 1);
+        store
+    }
+
+    #[test]
+    fn test_json_roundtrip_preserves_order() {
+        let store = sample_store();
+        let mut bytes = Vec::new();
+        Format::Json.save(&store, &mut bytes).unwrap();
+        let loaded = Format::Json.load(bytes.as_slice(), "keymap".to_string()).unwrap();
+        assert_eq!(loaded.order(), store.order());
+        assert_eq!(loaded.heatmap, store.heatmap);
+        assert_eq!(loaded.ngrams, store.ngrams);
+    }
+
+    #[test]
+    fn test_plist_roundtrip_preserves_order() {
+        let store = sample_store();
+        let mut bytes = Vec::new();
+        Format::Plist.save(&store, &mut bytes).unwrap();
+        let loaded = Format::Plist.load(bytes.as_slice(), "keymap".to_string()).unwrap();
+        assert_eq!(loaded.order(), store.order());
+        assert_eq!(loaded.heatmap, store.heatmap);
+        assert_eq!(loaded.ngrams, store.ngrams);
+    }
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(Format::from_path("keymap.json"), Format::Json);
+        assert_eq!(Format::from_path("keymap.plist"), Format::Plist);
+        assert_eq!(Format::from_path("keymap"), Format::Bare);
+    }
+
+    /// A flattened n-gram key used to contain a raw `\u{1}` separator byte,
+    /// which XML (and therefore plist) can't represent in character data
+    /// even escaped. Scan the written bytes for any XML 1.0 Char production
+    /// violation, independently of whether the `plist` crate's own reader
+    /// happens to tolerate it.
+    #[test]
+    fn test_plist_output_is_valid_xml() {
+        let store = sample_store();
+        let mut bytes = Vec::new();
+        Format::Plist.save(&store, &mut bytes).unwrap();
+        let text = String::from_utf8(bytes).expect("plist output must be UTF-8");
+        for c in text.chars() {
+            let is_valid_xml_char = matches!(c, '\u{9}' | '\u{A}' | '\u{D}')
+                || matches!(c, '\u{20}'..='\u{D7FF}')
+                || matches!(c, '\u{E000}'..='\u{FFFD}')
+                || matches!(c, '\u{10000}'..='\u{10FFFF}');
+            assert!(is_valid_xml_char, "invalid XML character {:?} in plist output", c);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use rdev::Key;
+
+use crate::corpus::KeystrokeHeatmap;
+
+/// Physical QWERTY rows, used to lay the heatmap out the way a keyboard
+/// actually looks rather than in whatever order `rdev::Key` declares its
+/// variants.
+const ROWS: [&[Key]; 4] = [
+    &[
+        Key::BackQuote,
+        Key::Num1,
+        Key::Num2,
+        Key::Num3,
+        Key::Num4,
+        Key::Num5,
+        Key::Num6,
+        Key::Num7,
+        Key::Num8,
+        Key::Num9,
+        Key::Num0,
+        Key::Minus,
+        Key::Equal,
+    ],
+    &[
+        Key::Tab,
+        Key::KeyQ,
+        Key::KeyW,
+        Key::KeyE,
+        Key::KeyR,
+        Key::KeyT,
+        Key::KeyY,
+        Key::KeyU,
+        Key::KeyI,
+        Key::KeyO,
+        Key::KeyP,
+        Key::LeftBracket,
+        Key::RightBracket,
+    ],
+    &[
+        Key::CapsLock,
+        Key::KeyA,
+        Key::KeyS,
+        Key::KeyD,
+        Key::KeyF,
+        Key::KeyG,
+        Key::KeyH,
+        Key::KeyJ,
+        Key::KeyK,
+        Key::KeyL,
+        Key::SemiColon,
+        Key::Quote,
+        Key::Return,
+    ],
+    &[
+        Key::ShiftLeft,
+        Key::KeyZ,
+        Key::KeyX,
+        Key::KeyC,
+        Key::KeyV,
+        Key::KeyB,
+        Key::KeyN,
+        Key::KeyM,
+        Key::Comma,
+        Key::Dot,
+        Key::Slash,
+        Key::ShiftRight,
+    ],
+];
+
+/// ANSI 256-color ramp from cold (least pressed) to hot (most pressed),
+/// picked from the 256-color cube for a blue-to-red heat gradient.
+const RAMP: [u8; 10] = [21, 27, 33, 39, 45, 220, 214, 208, 202, 196];
+
+/// Color used for keys that were never pressed.
+const COLD: u8 = 238;
+
+/// Render `heatmap` as a QWERTY-shaped grid of per-key press counts.
+/// Counts are mapped to `RAMP` by log-scaling against the busiest key, so a
+/// handful of very hot keys don't wash out the rest of the gradient. When
+/// `color` is false, no escape codes are emitted.
+pub fn render(heatmap: &KeystrokeHeatmap, color: bool) -> String {
+    let mut counts: HashMap<Key, u32> = HashMap::new();
+    for (ks, count) in heatmap {
+        *counts.entry(ks.key).or_insert(0) += count;
+    }
+    let max = counts.values().copied().max().unwrap_or(0);
+
+    let mut out = String::new();
+    for row in ROWS {
+        for key in row {
+            let count = counts.get(key).copied().unwrap_or(0);
+            let cell = format!(" {:>5}", count);
+            if color {
+                out.push_str(&format!("\x1b[38;5;{}m{}\x1b[0m", ramp_color(count, max), cell));
+            } else {
+                out.push_str(&cell);
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Quantize `count` into `RAMP`, log-scaled against `max`.
+fn ramp_color(count: u32, max: u32) -> u8 {
+    if count == 0 || max == 0 {
+        return COLD;
+    }
+    if count == max {
+        return *RAMP.last().unwrap();
+    }
+    let ratio = (count as f64).ln() / (max as f64).ln().max(f64::EPSILON);
+    let bucket = (ratio.clamp(0.0, 1.0) * (RAMP.len() - 1) as f64).round() as usize;
+    RAMP[bucket]
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::Keystroke;
+
+    #[test]
+    fn test_ramp_color_cold_for_unpressed() {
+        assert_eq!(ramp_color(0, 10), COLD);
+        assert_eq!(ramp_color(5, 0), COLD);
+    }
+
+    #[test]
+    fn test_ramp_color_hottest_at_max() {
+        assert_eq!(ramp_color(10, 10), *RAMP.last().unwrap());
+    }
+
+    #[test]
+    fn test_render_counts_key_across_interpretations() {
+        let mut heatmap = KeystrokeHeatmap::new();
+        heatmap.insert(
+            Keystroke {
+                key: Key::KeyA,
+                interpreted: "a".to_string(),
+            },
+            2,
+        );
+        heatmap.insert(
+            Keystroke {
+                key: Key::KeyA,
+                interpreted: "A".to_string(),
+            },
+            3,
+        );
+        let out = render(&heatmap, false);
+        assert!(out.contains("    5"));
+    }
+}
@@ -0,0 +1,8 @@
+pub mod corpus;
+pub mod crypto;
+pub mod format;
+pub mod heatmap;
+pub mod metrics;
+pub mod policy;
+pub mod store;
+pub mod tribuf;
@@ -1,11 +1,19 @@
 use std::fs::File;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use clap::{Args, Parser, Subcommand};
 use log::{error, info, warn};
-use rdev::listen;
+use rdev::{listen, Key};
 
 use konsone::corpus::Generator;
+use konsone::crypto;
+use konsone::format::Format;
+use konsone::heatmap;
+use konsone::metrics;
+use konsone::policy::{self, Policy};
 use konsone::store::{load, Store};
 
 #[derive(Parser)]
@@ -20,16 +28,84 @@ struct Cli {
 enum Commands {
     Log(Log),
     Gen(Gen),
+    Heat(Heat),
 }
 
 #[derive(Args)]
 struct Log {
     filename: Option<String>,
+
+    /// Serve live Prometheus metrics on this address while logging, e.g.
+    /// `127.0.0.1:9000`.
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Storage format for the store file. Guessed from the filename's
+    /// extension when omitted.
+    #[arg(long, value_enum)]
+    format: Option<Format>,
+
+    /// N-gram order to track, up to `store::MAX_ORDER`. Defaults to the
+    /// order of the loaded store, or trigrams (3) for a new store.
+    #[arg(long)]
+    order: Option<usize>,
+
+    /// Path to a TOML capture-policy file. Missing fields fall back to
+    /// defaults; the flags below override whatever the file sets.
+    #[arg(long)]
+    policy_file: Option<String>,
+
+    /// Max gap between two keystrokes, in milliseconds, for them to still
+    /// extend an n-gram.
+    #[arg(long)]
+    max_key_delay_ms: Option<u64>,
+
+    /// Gap, in milliseconds, after which the n-gram buffer resets rather
+    /// than bridging the pause.
+    #[arg(long)]
+    idle_timeout_ms: Option<u64>,
+
+    /// Key to never record, e.g. `ShiftLeft`; repeatable. Useful for
+    /// modifiers or keys typed into a password field.
+    #[arg(long = "ignore-key", value_parser = policy::parse_key)]
+    ignore_keys: Vec<Key>,
+
+    /// Encrypt the store at rest, establishing a passphrase from
+    /// `KONSONE_PASSPHRASE` or an interactive prompt if the store doesn't
+    /// already have one (e.g. a brand-new store, or an existing plaintext
+    /// one opting in).
+    #[arg(long)]
+    encrypt: bool,
 }
 
 #[derive(Args)]
 struct Gen {
     filename: Option<String>,
+
+    /// Storage format for the store file. Guessed from the filename's
+    /// extension when omitted.
+    #[arg(long, value_enum)]
+    format: Option<Format>,
+
+    /// N-gram order to generate with. Defaults to the order the store was
+    /// recorded with; can only narrow it, since a longer context than what
+    /// was recorded has no n-grams to draw from.
+    #[arg(long)]
+    order: Option<usize>,
+}
+
+#[derive(Args)]
+struct Heat {
+    filename: Option<String>,
+
+    /// Storage format for the store file. Guessed from the filename's
+    /// extension when omitted.
+    #[arg(long, value_enum)]
+    format: Option<Format>,
+
+    /// Strip ANSI color codes, e.g. when piping to a non-terminal.
+    #[arg(long)]
+    no_color: bool,
 }
 
 fn main() {
@@ -37,33 +113,114 @@ fn main() {
 
     let cli = Cli::parse();
     match cli.command {
-        Commands::Log(l) => log(l.filename.unwrap_or("keymap".to_string())),
-        Commands::Gen(g) => generate(g.filename.unwrap_or("keymap".to_string())),
+        Commands::Log(l) => log(
+            l.filename.unwrap_or("keymap".to_string()),
+            l.metrics_addr,
+            l.format,
+            l.order,
+            l.policy_file,
+            l.max_key_delay_ms,
+            l.idle_timeout_ms,
+            l.ignore_keys,
+            l.encrypt,
+        ),
+        Commands::Gen(g) => generate(g.filename.unwrap_or("keymap".to_string()), g.format, g.order),
+        Commands::Heat(h) => heat(h.filename.unwrap_or("keymap".to_string()), h.format, h.no_color),
     }
 }
 
-fn log(filename: String) {
+#[allow(clippy::too_many_arguments)]
+fn log(
+    filename: String,
+    metrics_addr: Option<SocketAddr>,
+    format: Option<Format>,
+    order: Option<usize>,
+    policy_file: Option<String>,
+    max_key_delay_ms: Option<u64>,
+    idle_timeout_ms: Option<u64>,
+    ignore_keys: Vec<Key>,
+    encrypt: bool,
+) {
+    let format = format.unwrap_or_else(|| Format::from_path(&filename));
     let mut store = match File::open(&filename) {
-        Ok(file) => load(file).unwrap_or_else(|err| {
+        Ok(file) => load(file, filename.clone(), Some(format)).unwrap_or_else(|err| {
             warn!("Error loading: {}", err);
             warn!("Creating new store");
             Store::new(filename)
         }),
         Err(_) => Store::new(filename),
     };
+    store.set_format(format);
+    if let Some(order) = order {
+        store.set_order(order);
+    }
+    if encrypt && store.passphrase().is_none() {
+        match crypto::passphrase() {
+            Ok(passphrase) => store.set_passphrase(Some(passphrase)),
+            Err(err) => {
+                error!("Error reading passphrase: {}", err);
+                return;
+            }
+        }
+    }
+    info!("Capturing n-grams up to order {}", store.order());
+
+    let mut policy = policy_file
+        .map(|path| Policy::load(&path))
+        .transpose()
+        .unwrap_or_else(|err| {
+            warn!("Error loading policy file: {}", err);
+            None
+        })
+        .unwrap_or_default();
+    if let Some(ms) = max_key_delay_ms {
+        policy.max_key_delay = Duration::from_millis(ms);
+    }
+    if let Some(ms) = idle_timeout_ms {
+        policy.idle_timeout = Duration::from_millis(ms);
+    }
+    if !ignore_keys.is_empty() {
+        policy.ignore_keys = ignore_keys;
+    }
+    info!(
+        "Capture policy: max key delay {:?}, idle timeout {:?}, ignoring {} key(s): {:?}",
+        policy.max_key_delay,
+        policy.idle_timeout,
+        policy.ignore_keys.len(),
+        policy.ignore_keys
+    );
+    store.set_policy(policy);
+
+    let store = Arc::new(Mutex::new(store));
+
+    if let Some(addr) = metrics_addr {
+        if let Err(err) = metrics::serve(addr, store.clone()) {
+            error!("Error starting metrics endpoint: {}", err);
+        }
+    }
+
     info!("Listening for events");
 
     // This will block.
-    if let Err(error) = listen(move |event| store.process_event(event)) {
+    if let Err(error) = listen(move |event| store.lock().expect("store mutex poisoned").process_event(event)) {
         error!("Error: {:?}", error);
     }
 }
 
-fn generate(filename: String) {
-    let store = load(File::open(&filename).expect("unable to open db")).expect("unable to load db");
+fn heat(filename: String, format: Option<Format>, no_color: bool) {
+    let store = load(File::open(&filename).expect("unable to open db"), filename, format)
+        .expect("unable to load db");
+    let color = !no_color && std::io::stdout().is_terminal();
+    print!("{}", heatmap::render(&store.heatmap, color));
+}
+
+fn generate(filename: String, format: Option<Format>, order: Option<usize>) {
+    let store = load(File::open(&filename).expect("unable to open db"), filename, format)
+        .expect("unable to load db");
     let mut outf = File::create("corpus.dat").expect("creation failed");
 
-    let corpus = Generator::new(&store.heatmap, &store.bigram, &store.trigram);
+    let order = order.map_or(store.order(), |order| order.min(store.order()));
+    let corpus = Generator::new(&store.heatmap, &store.ngrams, order);
 
     for ks in corpus {
         outf.write(ks.interpreted.as_bytes()).expect("write failed");
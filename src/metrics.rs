@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::info;
+use metrics::{describe_counter, describe_gauge, gauge};
+use metrics_exporter_prometheus::PrometheusBuilder;
+
+use crate::store::Store;
+
+/// How often the sliding-window gauges are recomputed.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Start the Prometheus scrape endpoint on `addr` and spawn a background
+/// thread that keeps the store-derived gauges up to date. Per-event counters
+/// (total keystrokes, per-key presses) are incremented directly by
+/// `Store::process_event` as events arrive; this only covers the metrics
+/// that need a point-in-time snapshot of the heatmaps.
+pub fn serve(addr: SocketAddr, store: Arc<Mutex<Store>>) -> Result<(), Box<dyn std::error::Error>> {
+    PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .install()?;
+
+    describe_counter!("konsone_keystrokes_total", "Total keystrokes recorded.");
+    describe_counter!(
+        "konsone_key_presses_total",
+        "Keystrokes recorded, labeled by key."
+    );
+    describe_gauge!("konsone_unique_keys", "Number of distinct keys seen.");
+    describe_gauge!(
+        "konsone_unique_ngrams",
+        "Number of distinct n-grams seen, labeled by n-gram length."
+    );
+    describe_gauge!(
+        "konsone_keystrokes_per_second",
+        "Keystrokes per second over a 1 second sliding window."
+    );
+
+    info!("Serving metrics on {}", addr);
+
+    std::thread::spawn(move || {
+        let mut last_total: u64 = 0;
+        loop {
+            std::thread::sleep(SAMPLE_INTERVAL);
+
+            let store = store.lock().expect("store mutex poisoned");
+            let total: u64 = store.heatmap.values().map(|&c| c as u64).sum();
+
+            gauge!("konsone_unique_keys").set(store.heatmap.len() as f64);
+
+            let mut ngrams_by_len: HashMap<usize, usize> = HashMap::new();
+            for gram in store.ngrams.keys() {
+                *ngrams_by_len.entry(gram.len()).or_insert(0) += 1;
+            }
+            for (len, count) in &ngrams_by_len {
+                gauge!("konsone_unique_ngrams", "len" => len.to_string()).set(*count as f64);
+            }
+            gauge!("konsone_keystrokes_per_second").set(
+                total.saturating_sub(last_total) as f64 / SAMPLE_INTERVAL.as_secs_f64(),
+            );
+            last_total = total;
+        }
+    });
+
+    Ok(())
+}
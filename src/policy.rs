@@ -0,0 +1,104 @@
+use std::error::Error;
+use std::time::Duration;
+
+use rdev::Key;
+use serde::{Deserialize, Serialize};
+
+/// Capture policy: what counts as "the same burst" for n-gram purposes, when
+/// a burst is abandoned entirely rather than bridged, and which keys are
+/// never recorded at all (e.g. modifiers, or keys typed into a password
+/// field). Loaded from an optional TOML file via [`Policy::load`] and then
+/// layered with CLI overrides, so every field must have a sensible default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    /// Max gap between two keystrokes for them to still extend an n-gram.
+    /// Tracked in milliseconds, unlike the whole-second granularity the
+    /// hardcoded delay used to have.
+    #[serde(with = "millis")]
+    pub max_key_delay: Duration,
+    /// Gap after which the n-gram buffer is reset outright, so a sequence
+    /// spanning a pause this long isn't counted as one run.
+    #[serde(with = "millis")]
+    pub idle_timeout: Duration,
+    /// Keys dropped before they ever reach `Store::update`.
+    pub ignore_keys: Vec<Key>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            max_key_delay: Duration::from_secs(2),
+            idle_timeout: Duration::from_secs(300),
+            ignore_keys: Vec::new(),
+        }
+    }
+}
+
+impl Policy {
+    /// Load a policy from a TOML file, falling back to [`Policy::default`]
+    /// for any field the file omits.
+    pub fn load(path: &str) -> Result<Policy, Box<dyn Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+}
+
+/// Parse a CLI `--ignore-key` value (a `rdev::Key` variant name, e.g.
+/// `ShiftLeft`) the same way [`crate::corpus::Keystroke::from_id`] round-trips
+/// a `Key` through serde: by feeding it to `Key`'s derived `Deserialize` as a
+/// quoted JSON string, rather than hand-matching every variant.
+pub fn parse_key(s: &str) -> Result<Key, String> {
+    serde_json::from_str(&format!("\"{}\"", s)).map_err(|e| e.to_string())
+}
+
+/// Serde (de)serialization of a `Duration` as a plain millisecond integer,
+/// so policy files write `max_key_delay = 500` rather than spelling out a
+/// `std::time::Duration` struct.
+mod millis {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(d.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(d)?))
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_policy() {
+        let policy = Policy::default();
+        assert_eq!(policy.max_key_delay, Duration::from_secs(2));
+        assert_eq!(policy.idle_timeout, Duration::from_secs(300));
+        assert!(policy.ignore_keys.is_empty());
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key("ShiftLeft").unwrap(), Key::ShiftLeft);
+        assert!(parse_key("NotAKey").is_err());
+    }
+
+    #[test]
+    fn test_load_partial_file_fills_defaults() {
+        let dir = std::env::temp_dir();
+        let path = dir.join("konsone_policy_test.toml");
+        std::fs::write(&path, "max_key_delay = 500\n").unwrap();
+
+        let policy = Policy::load(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(policy.max_key_delay, Duration::from_millis(500));
+        assert_eq!(policy.idle_timeout, Duration::from_secs(300));
+    }
+}
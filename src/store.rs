@@ -1,30 +1,76 @@
 use std::collections::HashMap;
 use std::error::Error;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use log::{info, warn};
 use rdev::{Event, EventType, Key};
 use serde::{Deserialize, Serialize};
 
-use crate::corpus::{BigramHeatmap, Keystroke, KeystrokeHeatmap, TrigramHeatmap};
+use crate::corpus::{Keystroke, KeystrokeHeatmap, NgramHeatmap};
+use crate::crypto::{self, DecryptingReader, EncryptingWriter};
+use crate::format::Format;
+use crate::policy::Policy;
 use crate::tribuf::Buffer;
 
-const MAX_KEY_DELAY: u64 = 2;
+/// Number of processed events between full checkpoints. Bounds worst-case
+/// data loss on crash to a single unreplayed event rather than a whole
+/// save interval, since every event in between is durably appended to the
+/// oplog as it's processed.
+const KEEP_STATE_EVERY: u64 = 64;
+
+/// Default n-gram order: counts (and the generator's Markov context) go up
+/// to trigrams unless `--order` says otherwise.
+pub const DEFAULT_ORDER: usize = 3;
+
+/// Highest order `--order` may select. Fixes the circular buffer's capacity
+/// at compile time; `Store::order` is the runtime value actually in effect,
+/// clamped to this.
+pub const MAX_ORDER: usize = 8;
 
 /// Store for keypresses.
 #[derive(Serialize, Deserialize)]
 pub struct Store {
     /// Heatmap of keypresses.
     pub heatmap: KeystrokeHeatmap,
-    /// Heatmap of bigrams.
-    pub bigram: BigramHeatmap,
-    /// Heatmap of trigrams.
-    pub trigram: TrigramHeatmap,
+    /// Heatmap of n-grams (runs of 2 or more keystrokes typed within the
+    /// delay window), up to `order` keystrokes long.
+    pub ngrams: NgramHeatmap,
 
-    ngrams: Buffer<EventWrapper>,
+    buffer: Buffer<EventWrapper, MAX_ORDER>,
     last_save: std::time::SystemTime,
     filename: String,
+    /// Sequence number of the last event folded into this checkpoint. Used
+    /// on load to know which tail of the oplog still needs replaying.
+    sequence: u64,
+    /// N-gram order in effect, i.e. the longest run tracked in `ngrams`.
+    /// Persisted so `konsone gen` reconstructs the same Markov context the
+    /// store was recorded with.
+    order: usize,
+    /// Passphrase used to encrypt the store at rest, if any. Read once from
+    /// `KONSONE_PASSPHRASE` at construction time so that background saves
+    /// never block on a prompt.
+    #[serde(skip)]
+    passphrase: Option<String>,
+    /// Handle to the append-only oplog, opened lazily on first write.
+    #[serde(skip)]
+    log_file: Option<File>,
+    /// On-disk serialization format used by `save`. Not itself persisted;
+    /// set once at construction/load time from the `--format` flag or the
+    /// filename's extension.
+    #[serde(skip)]
+    format: Format,
+    /// Capture policy in effect: key-delay window, idle timeout, and ignored
+    /// keys. Not persisted; set once at construction/load time from a policy
+    /// file and/or CLI flags, same as `format`.
+    #[serde(skip)]
+    policy: Policy,
+    /// Time of the last event folded in, used to detect an idle gap wider
+    /// than `policy.idle_timeout`. Not persisted: a restart starts a fresh
+    /// burst regardless of how long the store sat untouched.
+    #[serde(skip)]
+    last_event_time: Option<SystemTime>,
 }
 
 impl Store {
@@ -32,92 +78,313 @@ impl Store {
     pub fn new(filename: String) -> Store {
         Store {
             heatmap: HashMap::new(),
-            bigram: HashMap::new(),
-            trigram: HashMap::new(),
-            ngrams: Buffer::<EventWrapper>::new(),
+            ngrams: HashMap::new(),
+            buffer: Buffer::<EventWrapper, MAX_ORDER>::new(),
             last_save: SystemTime::now(),
-            filename: filename,
+            filename,
+            sequence: 0,
+            order: DEFAULT_ORDER,
+            passphrase: std::env::var(crypto::PASSPHRASE_ENV).ok(),
+            log_file: None,
+            format: Format::Bare,
+            policy: Policy::default(),
+            last_event_time: None,
         }
     }
 
-    /// Process a device event.
+    /// Build a store directly from already-computed heatmaps, used when
+    /// importing a `Json`/`Plist` export that doesn't carry the internal
+    /// replay state (ngram buffer, sequence number) a `Bare` checkpoint has.
+    pub fn from_heatmaps(
+        filename: String,
+        heatmap: KeystrokeHeatmap,
+        ngrams: NgramHeatmap,
+        order: usize,
+    ) -> Store {
+        Store {
+            heatmap,
+            ngrams,
+            order: order.clamp(1, MAX_ORDER),
+            ..Store::new(filename)
+        }
+    }
+
+    /// The n-gram order this store records (and that `Generator` should use
+    /// to match).
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    /// Set the n-gram order, clamped to `MAX_ORDER`.
+    pub fn set_order(&mut self, order: usize) {
+        self.order = order.clamp(1, MAX_ORDER);
+    }
+
+    /// Set the format used by subsequent calls to `save`.
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    /// Set the capture policy (key-delay window, idle timeout, ignore list)
+    /// used by subsequent events.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+
+    /// The passphrase subsequent calls to `save` encrypt with, if any.
+    pub fn passphrase(&self) -> Option<&str> {
+        self.passphrase.as_deref()
+    }
+
+    /// Set the passphrase subsequent calls to `save` encrypt with. Used to
+    /// establish encryption for a store that didn't already have one, e.g.
+    /// a brand-new store or a plaintext store opting in at load time.
+    pub fn set_passphrase(&mut self, passphrase: Option<String>) {
+        self.passphrase = passphrase;
+    }
+
+    /// The capture policy in effect, for logging it at startup.
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Process a device event, dropping it before it reaches `update` if its
+    /// key is on the policy's ignore list.
     pub fn process_event(&mut self, e: Event) {
         match e.event_type {
-            EventType::KeyPress(_) => self.update(EventWrapper(e)),
-            _ => return,
+            EventType::KeyPress(k) if !self.policy.ignore_keys.contains(&k) => {
+                self.update(EventWrapper(e))
+            }
+            _ => {}
         }
     }
 
-    /// Save the store to the filesystem.
+    /// Path of the append-only oplog backing this store's checkpoint file.
+    fn log_path(&self) -> String {
+        format!("{}.oplog", self.filename)
+    }
+
+    /// Durably append a single processed event to the oplog before folding
+    /// it into in-memory state, so a crash loses at most the event
+    /// currently being processed.
+    fn append_log(&mut self, sequence: u64, ew: &EventWrapper) -> Result<(), Box<dyn Error>> {
+        if self.log_file.is_none() {
+            self.log_file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.log_path())?,
+            );
+        }
+        let file = self.log_file.as_mut().expect("log file just opened");
+        let entry = LogEntry {
+            sequence,
+            event: ew.clone(),
+        };
+        let bytes = serde_bare::to_vec(&entry)?;
+        file.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        file.write_all(&bytes)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Save a full checkpoint of the heatmaps to the filesystem, encrypting
+    /// it at rest if a passphrase is configured, then truncate the oplog
+    /// since every event up to `self.sequence` is now captured in it.
     pub fn save(&mut self) -> Result<(), Box<dyn Error>> {
         info!("Saving to {}", self.filename);
 
-        let file = File::create(&self.filename)?;
-        serde_bare::to_writer(file, &self)?;
+        let file = BufWriter::new(File::create(&self.filename)?);
+        match &self.passphrase {
+            Some(passphrase) => {
+                let mut writer = EncryptingWriter::new(file, passphrase)?;
+                self.format.save(self, &mut writer)?;
+                let mut f = writer.finish()?;
+                f.flush()?;
+            }
+            None => {
+                let mut f = file;
+                self.format.save(self, &mut f)?;
+                f.flush()?;
+            }
+        }
+
+        self.log_file = None;
+        File::create(self.log_path())?;
 
         self.last_save = std::time::SystemTime::now();
         Ok(())
     }
 
     fn update(&mut self, ew: EventWrapper) {
-        self.ngrams.push(ew);
-        let events: Vec<Event> = self.ngrams.to_vec().into_iter().map(|ew| ew.0).collect();
+        self.sequence += 1;
+        self.append_log(self.sequence, &ew).unwrap_or_else(|err| {
+            warn!("Error appending to oplog: {}", err);
+        });
 
-        self.update_heatmap(&events[0]);
-        if is_within_delay(&events[0], &events[1]) {
-            self.update_bigram(&events[1], &events[0]);
-            if is_within_delay(&events[1], &events[2]) {
-                self.update_trigram(&events[2], &events[1], &events[0]);
-            }
+        self.apply(ew);
+
+        if self.sequence.is_multiple_of(KEEP_STATE_EVERY) {
+            self.save().unwrap_or_else(|err| {
+                warn!("Error saving: {}", err);
+            })
         }
+    }
 
-        // Store to file if last event is older than 10 minutes.
-        match self.last_save.elapsed() {
-            Ok(elapsed) => {
-                if elapsed.as_secs() > 600 {
-                    self.save().unwrap_or_else(|err| {
-                        warn!("Error saving: {}", err);
-                    })
-                }
+    /// Fold a single event into the heatmaps. Shared by live processing and
+    /// by oplog replay on load so both paths reconstruct identical counts.
+    fn apply(&mut self, ew: EventWrapper) {
+        let now = ew.0.time;
+        if let Some(last) = self.last_event_time {
+            if now.duration_since(last).unwrap_or_default() > self.policy.idle_timeout {
+                self.buffer = Buffer::new();
             }
-            Err(err) => {
-                warn!("Error getting elapsed time: {}", err);
-                self.save().unwrap_or_else(|err| {
-                    warn!("Error saving: {}", err);
-                })
+        }
+        self.last_event_time = Some(now);
+
+        self.buffer.push(ew);
+        let events: Vec<Event> = self.buffer.to_vec().into_iter().map(|ew| ew.0).collect();
+
+        self.update_heatmap(&events[0]);
+
+        // Emit every n-gram suffix from bigram up to `order`, stopping at
+        // the first gap wider than the delay window since a longer run
+        // can't be within-delay if a shorter prefix of it already isn't.
+        let order = self.order.min(MAX_ORDER);
+        let mut gram = vec![event_to_keystroke(&events[0])];
+        for n in 1..order {
+            if !is_within_delay(&events[n - 1], &events[n], self.policy.max_key_delay) {
+                break;
             }
+            gram.insert(0, event_to_keystroke(&events[n]));
+            let count = self.ngrams.entry(gram.clone()).or_insert(0);
+            *count += 1;
         }
     }
 
     fn update_heatmap(&mut self, e: &Event) {
         let ks = event_to_keystroke(e);
+        metrics::counter!("konsone_keystrokes_total").increment(1);
+        metrics::counter!("konsone_key_presses_total", "key" => format!("{:?}", ks.key))
+            .increment(1);
         let count = self.heatmap.entry(ks).or_insert(0);
         *count += 1;
     }
+}
 
-    fn update_bigram(&mut self, e1: &Event, e2: &Event) {
-        let ks1 = event_to_keystroke(e1);
-        let ks2 = event_to_keystroke(e2);
-        let count = self.bigram.entry((ks1, ks2)).or_insert(0);
-        *count += 1;
+/// Load a store named `filename` from a reader, transparently decrypting it
+/// if it was saved with a passphrase. Plaintext stores (sniffed via the
+/// absence of the [`crypto::MAGIC`] header) are read without decryption.
+/// Only the current `Store` layout is understood; a file written in an
+/// older layout is not read correctly.
+///
+/// `format` selects the storage format; when `None` it is guessed from
+/// `filename`'s extension. Whenever the format comes out `Bare`, given or
+/// guessed, the first byte of the (decrypted) payload is sniffed and a
+/// JSON or plist payload is loaded as such.
+///
+/// After deserializing the checkpoint, replays every oplog entry newer than
+/// its `sequence` so that at most the very last, not-yet-durable event is
+/// ever lost to a crash.
+pub fn load<R>(mut rdr: R, filename: String, format: Option<Format>) -> Result<Store, Box<dyn Error>>
+where
+    R: Read,
+{
+    let mut header = [0u8; 4];
+    let read = read_prefix(&mut rdr, &mut header)?;
+
+    let mut store: Store = if read == 4 && &header == crypto::MAGIC {
+        let passphrase = crypto::passphrase()?;
+        let decryptor = DecryptingReader::new(rdr, &passphrase)?;
+        let mut store = match format.unwrap_or_else(|| Format::from_path(&filename)) {
+            Format::Bare => {
+                let (format, reader) = Format::sniff(decryptor)?;
+                format.load(reader, filename.clone())?
+            }
+            format => format.load(decryptor, filename.clone())?,
+        };
+        // Retain the passphrase that actually decrypted this store, rather
+        // than re-reading the env var: if it came from an interactive
+        // prompt, losing it here would silently fall back to a plaintext
+        // save at the next checkpoint.
+        store.set_passphrase(Some(passphrase));
+        store
+    } else {
+        let prefixed = std::io::Cursor::new(header[..read].to_vec()).chain(rdr);
+        match format.unwrap_or_else(|| Format::from_path(&filename)) {
+            Format::Bare => {
+                let (format, reader) = Format::sniff(prefixed)?;
+                format.load(reader, filename.clone())?
+            }
+            format => format.load(prefixed, filename.clone())?,
+        }
+    };
+    replay_log(&mut store).unwrap_or_else(|err| {
+        warn!("Error replaying oplog: {}", err);
+    });
+    Ok(store)
+}
+
+/// Replay every oplog entry past the checkpoint's sequence number back into
+/// `store`, rebuilding the ngram buffer and heatmap counts exactly.
+fn replay_log(store: &mut Store) -> Result<(), Box<dyn Error>> {
+    let mut file = match File::open(store.log_path()) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut entries = Vec::new();
+    loop {
+        let mut len_bytes = [0u8; 4];
+        match file.read_exact(&mut len_bytes) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+        let len = u32::from_be_bytes(len_bytes) as usize;
+        let mut bytes = vec![0u8; len];
+        match file.read_exact(&mut bytes) {
+            Ok(()) => {}
+            // A crash between flushing the length prefix and the payload
+            // leaves a torn trailing record; treat it like any other
+            // end-of-log, not a reason to discard everything replayed so far.
+            Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+        entries.push(serde_bare::from_slice::<LogEntry>(&bytes)?);
     }
 
-    fn update_trigram(&mut self, e1: &Event, e2: &Event, e3: &Event) {
-        let ks1 = event_to_keystroke(e1);
-        let ks2 = event_to_keystroke(e2);
-        let ks3 = event_to_keystroke(e3);
-        let count = self.trigram.entry((ks1, ks2, ks3)).or_insert(0);
-        *count += 1;
+    entries.sort_by_key(|entry| entry.sequence);
+    for entry in entries {
+        if entry.sequence <= store.sequence {
+            continue;
+        }
+        store.apply(entry.event);
+        store.sequence = entry.sequence;
     }
+    Ok(())
 }
 
-/// Load a store from a reader.
-pub fn load<R>(rdr: R) -> Result<Store, Box<dyn Error>>
-where
-    R: std::io::Read,
-{
-    let store = serde_bare::from_reader(rdr)?;
-    Ok(store)
+/// A single oplog record: the event as processed, tagged with the
+/// monotonic sequence number it was assigned at that time.
+#[derive(Serialize, Deserialize)]
+struct LogEntry {
+    sequence: u64,
+    event: EventWrapper,
+}
+
+/// Fill `buf` with up to its length from `rdr`, stopping early on EOF.
+/// Returns the number of bytes actually read.
+fn read_prefix<R: Read>(rdr: &mut R, buf: &mut [u8; 4]) -> std::io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match rdr.read(&mut buf[total..])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
 }
 
 fn event_to_keystroke(e: &Event) -> Keystroke {
@@ -131,16 +398,15 @@ fn event_to_keystroke(e: &Event) -> Keystroke {
     }
 }
 
-/// Check if 2 events are occuring within the maximum key delay.
-fn is_within_delay(e1: &Event, e2: &Event) -> bool {
+/// Check if 2 events are occuring within `max_key_delay` of each other.
+fn is_within_delay(e1: &Event, e2: &Event, max_key_delay: Duration) -> bool {
     e1.time
         .duration_since(e2.time)
         .unwrap_or_else(|err| {
             warn!("Error getting elapsed time between events: {}", err);
-            Duration::from_secs(MAX_KEY_DELAY + 1)
+            max_key_delay.saturating_add(Duration::from_millis(1))
         })
-        .as_secs()
-        < MAX_KEY_DELAY
+        < max_key_delay
 }
 
 /// Wrapper for an event to allow for Default trait implementation.
@@ -214,28 +480,69 @@ mod tests {
             event_type: EventType::KeyPress(Key::KeyB),
             name: "b".to_string().into(),
         });
+        let bigrams = store.ngrams.iter().filter(|(gram, _)| gram.len() == 2).count();
+        let trigrams = store.ngrams.iter().filter(|(gram, _)| gram.len() == 3).count();
+
         assert_eq!(
             store.heatmap.len(),
             2,
             "Expected 2 keystrokes, got {:?}",
             store.heatmap
         );
-        assert_eq!(
-            store.bigram.len(),
-            2,
-            "Expected 2 bigrams, got {:?}",
-            store.bigram
-        );
-        assert_eq!(
-            store.trigram.len(),
-            1,
-            "Expected 1 trigram, got {:?}",
-            store.trigram
-        );
+        assert_eq!(bigrams, 2, "Expected 2 bigrams, got {:?}", store.ngrams);
+        assert_eq!(trigrams, 1, "Expected 1 trigram, got {:?}", store.ngrams);
         assert_eq!(store.heatmap[&ka], 2);
         assert_eq!(store.heatmap[&kb], 2);
-        assert_eq!(store.bigram[&(ka.clone(), ka.clone())], 1);
-        assert_eq!(store.bigram[&(ka.clone(), kb.clone())], 1);
-        assert_eq!(store.trigram[&(ka.clone(), ka.clone(), kb.clone())], 1);
+        assert_eq!(store.ngrams[&vec![ka.clone(), ka.clone()]], 1);
+        assert_eq!(store.ngrams[&vec![ka.clone(), kb.clone()]], 1);
+        assert_eq!(store.ngrams[&vec![ka.clone(), ka.clone(), kb.clone()]], 1);
+    }
+
+    #[test]
+    fn test_crash_recovery_replays_unsaved_events() {
+        let dir = std::env::temp_dir();
+        let path = dir.join("konsone_store_crash_test.db");
+        let filename = path.to_str().unwrap().to_string();
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(format!("{}.oplog", filename)).ok();
+
+        let ka = Keystroke {
+            key: Key::KeyA,
+            interpreted: "a".to_string(),
+        };
+        let kb = Keystroke {
+            key: Key::KeyB,
+            interpreted: "b".to_string(),
+        };
+
+        let mut store = Store::new(filename.clone());
+        store.process_event(Event {
+            time: SystemTime::now(),
+            event_type: EventType::KeyPress(Key::KeyA),
+            name: "a".to_string().into(),
+        });
+        store.save().unwrap();
+
+        // Simulate a crash: these events are durably appended to the oplog
+        // but never folded into a checkpoint via save().
+        store.process_event(Event {
+            time: SystemTime::now(),
+            event_type: EventType::KeyPress(Key::KeyB),
+            name: "b".to_string().into(),
+        });
+        store.process_event(Event {
+            time: SystemTime::now(),
+            event_type: EventType::KeyPress(Key::KeyB),
+            name: "b".to_string().into(),
+        });
+        drop(store);
+
+        let reloaded = load(File::open(&path).unwrap(), filename.clone(), None).unwrap();
+
+        std::fs::remove_file(&path).ok();
+        std::fs::remove_file(format!("{}.oplog", filename)).ok();
+
+        assert_eq!(reloaded.heatmap[&ka], 1);
+        assert_eq!(reloaded.heatmap[&kb], 2);
     }
 }
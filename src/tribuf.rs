@@ -1,17 +1,109 @@
-use serde::{Deserialize, Serialize};
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use serde::Deserialize as DeriveDeserialize;
+use std::marker::PhantomData;
 
-/// Buffer is a naive implementation of a circular buffer of size 3,
-/// with a single cursor.
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Buffer<T> {
-    data: [T; 3],
+/// Buffer is a naive circular buffer of fixed size `N`, with a single
+/// cursor.
+#[derive(Debug)]
+pub struct Buffer<T, const N: usize> {
+    data: [T; N],
     cursor: usize,
 }
 
-impl<T: std::default::Default + Clone> Buffer<T> {
+// `serde`'s derive only has array impls for literal lengths 0..=32, not for
+// a const-generic `[T; N]`, so `Buffer` needs its own `Serialize`/
+// `Deserialize` impls that go through a slice/`Vec` instead.
+impl<T: Serialize, const N: usize> Serialize for Buffer<T, N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Buffer", 2)?;
+        state.serialize_field("data", &self.data[..])?;
+        state.serialize_field("cursor", &self.cursor)?;
+        state.end()
+    }
+}
+
+impl<'de, T: Deserialize<'de> + Clone, const N: usize> Deserialize<'de> for Buffer<T, N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(DeriveDeserialize)]
+        #[serde(field_identifier, rename_all = "lowercase")]
+        enum Field {
+            Data,
+            Cursor,
+        }
+
+        struct BufferVisitor<T, const N: usize>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de> + Clone, const N: usize> Visitor<'de> for BufferVisitor<T, N> {
+            type Value = Buffer<T, N>;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("struct Buffer")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let data: Vec<T> = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                let cursor = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                vec_to_buffer(data, cursor)
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut data: Option<Vec<T>> = None;
+                let mut cursor: Option<usize> = None;
+                while let Some(key) = map.next_key()? {
+                    match key {
+                        Field::Data => {
+                            if data.is_some() {
+                                return Err(de::Error::duplicate_field("data"));
+                            }
+                            data = Some(map.next_value()?);
+                        }
+                        Field::Cursor => {
+                            if cursor.is_some() {
+                                return Err(de::Error::duplicate_field("cursor"));
+                            }
+                            cursor = Some(map.next_value()?);
+                        }
+                    }
+                }
+                let data = data.ok_or_else(|| de::Error::missing_field("data"))?;
+                let cursor = cursor.ok_or_else(|| de::Error::missing_field("cursor"))?;
+                vec_to_buffer(data, cursor)
+            }
+        }
+
+        fn vec_to_buffer<T: Clone, E: de::Error, const N: usize>(
+            data: Vec<T>,
+            cursor: usize,
+        ) -> Result<Buffer<T, N>, E> {
+            if data.len() != N {
+                return Err(de::Error::invalid_length(data.len(), &N.to_string().as_str()));
+            }
+            let data: [T; N] = match data.try_into() {
+                Ok(arr) => arr,
+                Err(_) => unreachable!("length checked above"),
+            };
+            Ok(Buffer { data, cursor })
+        }
+
+        deserializer.deserialize_struct("Buffer", &["data", "cursor"], BufferVisitor(PhantomData))
+    }
+}
+
+impl<T: std::default::Default + Clone, const N: usize> Default for Buffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: std::default::Default + Clone, const N: usize> Buffer<T, N> {
     pub fn new() -> Self {
         Self {
-            data: [Default::default(), Default::default(), Default::default()],
+            data: std::array::from_fn(|_| T::default()),
             cursor: 0,
         }
     }
@@ -19,14 +111,14 @@ impl<T: std::default::Default + Clone> Buffer<T> {
     /// Push a value to the buffer.
     pub fn push(&mut self, value: T) {
         self.data[self.cursor] = value;
-        self.cursor = (self.cursor + 1) % 3;
+        self.cursor = (self.cursor + 1) % N;
     }
 
     /// Return a vector of the buffer's non empty data, from newest to oldest.
     pub fn to_vec(&self) -> Vec<T> {
-        let mut result = Vec::new();
-        for i in (self.cursor..self.cursor + 3).rev() {
-            result.push(self.data[i % 3].clone());
+        let mut result = Vec::with_capacity(N);
+        for i in (self.cursor..self.cursor + N).rev() {
+            result.push(self.data[i % N].clone());
         }
         result
     }
@@ -38,7 +130,7 @@ mod tests {
 
     #[test]
     fn test_buffer() {
-        let mut buf = Buffer::<i32>::new();
+        let mut buf = Buffer::<i32, 3>::new();
         buf.push(1);
         assert_eq!(buf.to_vec(), vec![1, 0, 0]);
         buf.push(2);
@@ -49,4 +141,13 @@ mod tests {
         buf.push(6);
         assert_eq!(buf.to_vec(), vec![6, 5, 4]);
     }
+
+    #[test]
+    fn test_buffer_generic_order() {
+        let mut buf = Buffer::<i32, 5>::new();
+        for v in 1..=7 {
+            buf.push(v);
+        }
+        assert_eq!(buf.to_vec(), vec![7, 6, 5, 4, 3]);
+    }
 }